@@ -1,20 +1,29 @@
 use std::{
-    collections::HashMap, env, fmt::Result, net::SocketAddr, sync::{Arc, Mutex}
+    collections::{HashMap, HashSet}, env, fmt::Result, fs::File, io::BufReader, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant}
 };
 
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::{net::{TcpListener, TcpStream}, sync::broadcast};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpListener, sync::mpsc};
+use tokio_rustls::{rustls::{pki_types::{CertificateDer, PrivateKeyDer}, ServerConfig}, TlsAcceptor};
+use tokio_tungstenite::{accept_hdr_async, tungstenite::{handshake::server::{Request, Response}, http::HeaderValue, Message}};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct User {
     user_id: String,
     name: String,
     avatar: String,
+    repo_id: String,
     current_file: Option<String>,
+    #[serde(skip, default = "Instant::now")]
+    last_seen: Instant,
 }
 
+/// How long a user may go without any client message before the reaper drops them.
+const USER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the reaper task wakes up to sweep stale users.
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct FileActivity {
     user_id: String,
@@ -27,6 +36,7 @@ struct FileActivity {
 enum ClientMessage {
     Register(User),
     FileFocus { file_path: String, repo_id: String },
+    Ping,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -34,73 +44,241 @@ enum ClientMessage {
 enum ServerMessage {
     UserUpdate(HashMap<String, User>),
     FileActivityUpdate(FileActivity),
+    Pong,
+}
+
+/// Depth of each peer's outbound queue. A slow reader fills only its own queue
+/// and does not hold up other peers; once that queue is full, further messages
+/// for that peer are dropped (`try_send`), not waited on.
+const PEER_QUEUE: usize = 100;
+
+/// Monotonic source of per-connection ids, used to distinguish a reconnect with
+/// the same `user_id` from the stale socket it replaced.
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A connected client's outbound side: a dedicated queue plus the send task
+/// that drains it to the socket.
+struct Peer {
+    conn_id: u64,
+    tx: mpsc::Sender<ServerMessage>,
+    handle: tokio::task::JoinHandle<()>,
 }
 
 struct AppState {
     active_users: Mutex<HashMap<String, User>>,
+    /// Peers grouped by `repo_id`, then keyed by `user_id`, holding each client's
+    /// private delivery queue. Nesting by room keeps fan-out O(room) rather than
+    /// scanning every connected client.
+    peers: Mutex<HashMap<String, HashMap<String, Peer>>>,
+}
+
+impl AppState {
+    /// Deliver a message to every peer in `repo_id`.
+    fn broadcast_room(&self, repo_id: &str, msg: &ServerMessage) {
+        let peers = self.peers.lock().unwrap();
+        if let Some(room) = peers.get(repo_id) {
+            for peer in room.values() {
+                let _ = peer.tx.try_send(msg.clone());
+            }
+        }
+    }
+
+    /// Deliver a message to a single peer, if it is still connected. Enables
+    /// unicast replies (e.g. "who is on this file") in addition to room fan-out.
+    #[allow(dead_code)]
+    fn unicast(&self, repo_id: &str, user_id: &str, msg: ServerMessage) {
+        let peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get(repo_id).and_then(|room| room.get(user_id)) {
+            let _ = peer.tx.try_send(msg);
+        }
+    }
+}
+
+/// Wire format negotiated per connection at upgrade time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Json,
+    MsgPack,
 }
 
-async fn handle_connection(
-    ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
+impl Codec {
+    /// Encode an outbound `ServerMessage` into the appropriate frame.
+    fn encode(self, msg: &ServerMessage) -> Message {
+        match self {
+            Codec::Json => Message::Text(serde_json::to_string(msg).unwrap().into()),
+            Codec::MsgPack => Message::Binary(rmp_serde::to_vec_named(msg).unwrap().into()),
+        }
+    }
+
+    /// Decode an inbound frame into a `ClientMessage`, if it carries one.
+    fn decode(self, msg: &Message) -> Option<ClientMessage> {
+        match (self, msg) {
+            (Codec::Json, Message::Text(text)) => serde_json::from_str(text).ok(),
+            (Codec::MsgPack, Message::Binary(bytes)) => rmp_serde::from_slice(bytes).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Pick the wire format from a `?format=msgpack` query parameter or a `msgpack`
+/// WebSocket subprotocol on the upgrade request.
+fn negotiate_codec(req: &Request) -> Codec {
+    if let Some(query) = req.uri().query() {
+        if query.split('&').any(|p| p == "format=msgpack") {
+            return Codec::MsgPack;
+        }
+    }
+    if let Some(proto) = req.headers().get("sec-websocket-protocol") {
+        if let Ok(proto) = proto.to_str() {
+            if proto.split(',').any(|p| p.trim() == "msgpack") {
+                return Codec::MsgPack;
+            }
+        }
+    }
+    Codec::Json
+}
+
+/// Perform the WebSocket handshake while negotiating the wire format.
+async fn accept_ws<S>(stream: S) -> Option<(tokio_tungstenite::WebSocketStream<S>, Codec)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let negotiated = Arc::new(Mutex::new(Codec::Json));
+    let slot = Arc::clone(&negotiated);
+    let ws = accept_hdr_async(stream, move |req: &Request, mut resp: Response| {
+        let codec = negotiate_codec(req);
+        // Only echo the subprotocol when the client actually offered it.
+        if codec == Codec::MsgPack {
+            if let Some(proto) = req.headers().get("sec-websocket-protocol") {
+                if proto.to_str().map(|p| p.split(',').any(|p| p.trim() == "msgpack")).unwrap_or(false) {
+                    resp.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_static("msgpack"));
+                }
+            }
+        }
+        *slot.lock().unwrap() = codec;
+        Ok(resp)
+    })
+    .await
+    .ok()?;
+    let codec = *negotiated.lock().unwrap();
+    Some((ws, codec))
+}
+
+/// Snapshot of the users currently in a single room.
+fn room_users(users: &HashMap<String, User>, repo_id: &str) -> HashMap<String, User> {
+    users
+        .iter()
+        .filter(|(_, u)| u.repo_id == repo_id)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+async fn handle_connection<S>(
+    ws_stream: tokio_tungstenite::WebSocketStream<S>,
     state: Arc<AppState>,
-    tx: broadcast::Sender<ServerMessage>,
-) {
+    codec: Codec,
+)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     println!("New websocket connection");
 
+    let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+
     let (mut ws_sender, mut ws_recv) = ws_stream.split();
-    let mut rx = tx.subscribe();
+
+    // This connection's private outbound queue. It is registered in the peer
+    // registry at `Register` time so other connections can target it directly.
+    let (peer_tx, mut peer_rx) = mpsc::channel::<ServerMessage>(PEER_QUEUE);
 
     let mut curr_user_id = None;
+    let mut curr_repo_id: Option<String> = None;
 
-    let send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let json = serde_json::to_string(&msg).unwrap();
-            if ws_sender.send(Message::Text(json.into())).await.is_err() {
+    let mut send_task = Some(tokio::spawn(async move {
+        while let Some(msg) = peer_rx.recv().await {
+            if ws_sender.send(codec.encode(&msg)).await.is_err() {
                 break;
             }
         }
-    });
+    }));
 
     while let Some(res) = ws_recv.next().await {
         match res {
             Ok(msg) => {
-                if let Message::Text(text) = msg {
-                    if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                        match client_msg {
-                            ClientMessage::Register(user) => {
-                                println!("Reg {} ",  user.user_id.clone());
-                                curr_user_id = Some(user.user_id.clone());
-
-                                // Store users in active users
-                                {
-                                    let mut users = state.active_users.lock().unwrap();
-                                    users.insert(user.user_id.clone(), user);
-                                } // Scope should call drop on the mutex so it should unlock??? 
+                if let Some(client_msg) = codec.decode(&msg) {
+                    // Any decoded client message counts as a sign of life.
+                    if let Some(ref user_id) = curr_user_id {
+                        let mut users = state.active_users.lock().unwrap();
+                        if let Some(user) = users.get_mut(user_id) {
+                            user.last_seen = Instant::now();
+                        }
+                    }
+
+                    match client_msg {
+                        ClientMessage::Register(mut user) => {
+                            println!("Reg {} ", user.user_id.clone());
+                            curr_user_id = Some(user.user_id.clone());
+                            curr_repo_id = Some(user.repo_id.clone());
+                            user.last_seen = Instant::now();
 
-                                let users = state.active_users.lock().unwrap().clone();
-                                let _ = tx.send(ServerMessage::UserUpdate(users));
+                            let repo_id = user.repo_id.clone();
+
+                            // Register this connection's queue in the peer
+                            // registry so room broadcasts and unicasts reach it.
+                            if let Some(handle) = send_task.take() {
+                                let mut peers = state.peers.lock().unwrap();
+                                let displaced = peers
+                                    .entry(repo_id.clone())
+                                    .or_default()
+                                    .insert(user.user_id.clone(), Peer {
+                                        conn_id,
+                                        tx: peer_tx.clone(),
+                                        handle,
+                                    });
+                                // A reconnect under the same user_id displaces the
+                                // old socket's peer; abort its send task so it can't
+                                // leak waiting on a queue nobody drains.
+                                if let Some(old) = displaced {
+                                    old.handle.abort();
+                                }
                             }
 
-                            ClientMessage::FileFocus { file_path, repo_id } => {
-                                if let Some(ref user_id) = curr_user_id {
-                                    {
-                                        let mut users = state.active_users.lock().unwrap();
-                                        if let Some(user) = users.get_mut(user_id) {
-                                            user.current_file = Some(file_path.clone());
-                                        }
-                                    }
+                            // Store users in active users
+                            {
+                                let mut users = state.active_users.lock().unwrap();
+                                users.insert(user.user_id.clone(), user);
+                            } // Scope should call drop on the mutex so it should unlock???
 
-                                    println!("File focus {}", file_path.clone());
-                                    let activity = FileActivity {
-                                        user_id: user_id.clone(),
-                                        file_path,
-                                        repo_id,
-                                    };
+                            let snapshot = {
+                                let users = state.active_users.lock().unwrap();
+                                room_users(&users, &repo_id)
+                            };
+                            state.broadcast_room(&repo_id, &ServerMessage::UserUpdate(snapshot));
+                        }
 
-                                    let _ = tx.send(ServerMessage::FileActivityUpdate(activity));
+                        ClientMessage::FileFocus { file_path, repo_id } => {
+                            if let Some(ref user_id) = curr_user_id {
+                                {
+                                    let mut users = state.active_users.lock().unwrap();
+                                    if let Some(user) = users.get_mut(user_id) {
+                                        user.current_file = Some(file_path.clone());
+                                    }
                                 }
+
+                                println!("File focus {}", file_path.clone());
+                                let activity = FileActivity {
+                                    user_id: user_id.clone(),
+                                    file_path,
+                                    repo_id: repo_id.clone(),
+                                };
+
+                                state.broadcast_room(&repo_id, &ServerMessage::FileActivityUpdate(activity));
                             }
                         }
+
+                        ClientMessage::Ping => {
+                            let _ = peer_tx.try_send(ServerMessage::Pong);
+                        }
                     }
                 }
             }
@@ -110,45 +288,213 @@ async fn handle_connection(
 
     if let Some(user_id) = curr_user_id {
         {
-            let mut users = state.active_users.lock().unwrap(); 
+            let mut users = state.active_users.lock().unwrap();
             users.remove(&user_id);
-        } 
-        
-        let users = state.active_users.lock().unwrap().clone(); 
-        let _ = tx.send(ServerMessage::UserUpdate(users)); 
+        }
+
+        // Drop this client's peer entry and tear down its send task cleanly, but
+        // only if the stored peer is still this connection — a newer reconnect may
+        // already own the slot, and we must not cut that live client off.
+        if let Some(ref repo_id) = curr_repo_id {
+            let mut peers = state.peers.lock().unwrap();
+            if let Some(room) = peers.get_mut(repo_id) {
+                if room.get(&user_id).map(|p| p.conn_id) == Some(conn_id) {
+                    if let Some(peer) = room.remove(&user_id) {
+                        peer.handle.abort();
+                    }
+                }
+                if room.is_empty() {
+                    peers.remove(repo_id);
+                }
+            }
+        }
+
+        if let Some(repo_id) = curr_repo_id {
+            let snapshot = {
+                let users = state.active_users.lock().unwrap();
+                room_users(&users, &repo_id)
+            };
+            state.broadcast_room(&repo_id, &ServerMessage::UserUpdate(snapshot));
+        }
     }
 
-    send_task.abort();
+    // Never registered (disconnected before `Register`): abort the orphan task.
+    if let Some(send_task) = send_task {
+        send_task.abort();
+    }
     println!("websocket connection closed");
 }
 
 
-#[tokio::main] 
+fn load_tls_acceptor(cert_path: &str, key_path: &str) -> TlsAcceptor {
+    let certs: Vec<CertificateDer<'static>> =
+        rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path).unwrap()))
+            .map(|c| c.unwrap())
+            .collect();
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path).unwrap()))
+        .unwrap()
+        .expect("no private key found in key file");
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKeyDer::from(key))
+        .expect("invalid certificate/key");
+
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Adopt the listening socket handed over by the service manager via the
+/// `LISTEN_FDS` protocol. The first passed descriptor is `SD_LISTEN_FDS_START`.
+#[cfg(feature = "socket-activation")]
+fn socket_activated_listener() -> std::net::TcpListener {
+    use std::os::fd::FromRawFd;
+
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    // LISTEN_PID names the process the descriptors were meant for; refuse to
+    // adopt fds inherited by a child or unrelated process.
+    if let Some(pid) = env::var("LISTEN_PID").ok().and_then(|v| v.parse::<u32>().ok()) {
+        assert!(
+            pid == std::process::id(),
+            "LISTEN_PID {} does not match this process {}",
+            pid,
+            std::process::id(),
+        );
+    }
+
+    let fds: i32 = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .expect("LISTEN_FDS not set; started outside socket activation?");
+    assert!(fds >= 1, "expected at least one socket-activation fd");
+
+    // SAFETY: the service manager guarantees SD_LISTEN_FDS_START is a valid
+    // listening socket for the lifetime of the process.
+    unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) }
+}
+
+#[tokio::main]
 async fn main() -> Result {
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:3030".to_string());
+    let mut addr = None;
+    let mut cert = None;
+    let mut key = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cert" => cert = args.next(),
+            "--key" => key = args.next(),
+            other => addr = Some(other.to_string()),
+        }
+    }
 
-    let addr = addr.parse::<SocketAddr>().unwrap();
-    println!("Server running on {}", addr);
+    let addr = addr.unwrap_or_else(|| "127.0.0.1:3030".to_string());
+
+    let acceptor = match (cert, key) {
+        (Some(cert), Some(key)) => {
+            println!("TLS enabled");
+            Some(load_tls_acceptor(&cert, &key))
+        }
+        _ => None,
+    };
 
     let state = Arc::new(AppState{
         active_users: Mutex::new(HashMap::new()),
+        peers: Mutex::new(HashMap::new()),
     });
 
-    let (tx, _) = broadcast::channel::<ServerMessage>(100);
-    let listener = TcpListener::bind(&addr).await.unwrap();
+    // Reaper: periodically drop users that have gone silent past USER_TIMEOUT so a
+    // dropped connection or crashed client doesn't leave a ghost in the roster.
+    // Each room whose membership changed gets a fresh UserUpdate.
+    {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let stale: Vec<(String, String)> = {
+                    let mut users = state.active_users.lock().unwrap();
+                    let stale: Vec<(String, String)> = users
+                        .values()
+                        .filter(|u| u.last_seen.elapsed() >= USER_TIMEOUT)
+                        .map(|u| (u.user_id.clone(), u.repo_id.clone()))
+                        .collect();
+                    users.retain(|_, u| u.last_seen.elapsed() < USER_TIMEOUT);
+                    stale
+                };
+
+                // Tear down the peer entry and its send task for each reaped user,
+                // otherwise a crashed client's queue would leak until the next write.
+                {
+                    let mut peers = state.peers.lock().unwrap();
+                    for (user_id, repo_id) in &stale {
+                        if let Some(room) = peers.get_mut(repo_id) {
+                            if let Some(peer) = room.remove(user_id) {
+                                peer.handle.abort();
+                            }
+                            if room.is_empty() {
+                                peers.remove(repo_id);
+                            }
+                        }
+                    }
+                }
+
+                let touched_repos: HashSet<String> =
+                    stale.into_iter().map(|(_, repo_id)| repo_id).collect();
+
+                for repo_id in touched_repos {
+                    let snapshot = {
+                        let users = state.active_users.lock().unwrap();
+                        room_users(&users, &repo_id)
+                    };
+                    state.broadcast_room(&repo_id, &ServerMessage::UserUpdate(snapshot));
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "socket-activation")]
+    let listener = {
+        // The CLI address is ignored under socket activation; the socket is the
+        // descriptor the service manager passed via LISTEN_FDS.
+        let _ = &addr;
+        let std_listener = socket_activated_listener();
+        std_listener.set_nonblocking(true).unwrap();
+        println!("Adopted socket-activated listener (LISTEN_FDS)");
+        TcpListener::from_std(std_listener).unwrap()
+    };
+    #[cfg(not(feature = "socket-activation"))]
+    let listener = {
+        println!("Server running on {}", addr);
+        TcpListener::bind(&addr).await.unwrap()
+    };
 
     while let Ok((stream, _)) = listener.accept().await {
-        let ws_stream = accept_async(stream).await.expect("Failed to accept a websocket");
-        let state_cl = Arc::clone(&state); 
-        let tx_cl = tx.clone();
+        let state_cl = Arc::clone(&state);
+        let acceptor_cl = acceptor.clone();
 
         tokio::spawn(async move {
-            handle_connection(ws_stream, state_cl, tx_cl).await;
+            match acceptor_cl {
+                Some(acceptor) => {
+                    let tls_stream = match acceptor.accept(stream).await {
+                        Ok(s) => s,
+                        Err(e) => {
+                            eprintln!("TLS handshake failed: {}", e);
+                            return;
+                        }
+                    };
+                    let Some((ws_stream, codec)) = accept_ws(tls_stream).await else { return; };
+                    handle_connection(ws_stream, state_cl, codec).await;
+                }
+                None => {
+                    let Some((ws_stream, codec)) = accept_ws(stream).await else { return; };
+                    handle_connection(ws_stream, state_cl, codec).await;
+                }
+            }
         });
 
-    } 
+    }
 
     println!("Hello, world!");
     Ok(())